@@ -0,0 +1,210 @@
+//! Hybrid in-memory / on-disk store for buffered block bytes.
+//!
+//! Blocks sitting in the rollback buffer waiting to reach `min_depth` are
+//! usually small, but a deep `min_depth` or a long `Await` window can pile
+//! up enough of them to matter. Blocks under `inline_threshold` stay in
+//! memory; anything bigger is spilled to a content-addressed file keyed by
+//! its block hash, with only a small handle kept around.
+
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use pallas::network::miniprotocols::Point;
+
+use crate::Error;
+
+const DEFAULT_INLINE_THRESHOLD: usize = 16 * 1024;
+
+enum Handle {
+    Inline(Vec<u8>),
+    Spilled(PathBuf),
+}
+
+pub struct BlockStore {
+    inline_threshold: usize,
+    spill_dir: PathBuf,
+    entries: HashMap<Point, Handle>,
+}
+
+impl BlockStore {
+    pub fn new(spill_dir: PathBuf, inline_threshold: Option<usize>) -> Self {
+        Self {
+            inline_threshold: inline_threshold.unwrap_or(DEFAULT_INLINE_THRESHOLD),
+            spill_dir,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn spill_path(&self, point: &Point) -> PathBuf {
+        let hash = match point {
+            Point::Origin => "origin".to_string(),
+            Point::Specific(_, hash) => hex::encode(hash),
+        };
+
+        self.spill_dir.join(hash)
+    }
+
+    /// the inline/spill cutoff this store was configured with, so callers
+    /// can decide whether a block's bytes are worth duplicating elsewhere
+    pub fn inline_threshold(&self) -> usize {
+        self.inline_threshold
+    }
+
+    pub fn insert(&mut self, point: Point, bytes: Vec<u8>) -> Result<(), Error> {
+        if bytes.len() <= self.inline_threshold {
+            self.entries.insert(point, Handle::Inline(bytes));
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.spill_dir)?;
+        let path = self.spill_path(&point);
+        fs::write(&path, &bytes)?;
+        self.entries.insert(point, Handle::Spilled(path));
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, point: &Point) -> Result<Option<Vec<u8>>, Error> {
+        match self.entries.remove(point) {
+            Some(Handle::Inline(bytes)) => Ok(Some(bytes)),
+            Some(Handle::Spilled(path)) => {
+                let bytes = fs::read(&path)?;
+                let _ = fs::remove_file(&path);
+                Ok(Some(bytes))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// drop every entry whose point is strictly after the rollback slot,
+    /// deleting any spilled files along the way
+    pub fn retain_up_to_slot(&mut self, slot: u64) -> Result<(), Error> {
+        let stale: Vec<Point> = self
+            .entries
+            .keys()
+            .filter(|p| p.slot_or_default() > slot)
+            .cloned()
+            .collect();
+
+        for point in stale {
+            if let Some(Handle::Spilled(path)) = self.entries.remove(&point) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// recognize a block that was already spilled to disk on a previous run
+    /// (the file outlives process restarts) without requiring its bytes to
+    /// be duplicated anywhere else. Returns `false` if no such file exists.
+    pub fn adopt_spilled(&mut self, point: Point) -> Result<bool, Error> {
+        let path = self.spill_path(&point);
+
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        self.entries.insert(point, Handle::Spilled(path));
+        Ok(true)
+    }
+
+    pub fn clear(&mut self) {
+        for (_, handle) in self.entries.drain() {
+            if let Handle::Spilled(path) = handle {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_spill_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "oura-test-blocks-{name}-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn point(slot: u64, hash_byte: u8) -> Point {
+        Point::Specific(slot, vec![hash_byte; 32])
+    }
+
+    #[test]
+    fn inline_blocks_round_trip_through_insert_and_remove() {
+        let dir = temp_spill_dir("inline");
+        let mut store = BlockStore::new(dir.clone(), Some(1024));
+
+        let p = point(10, 1);
+        store.insert(p.clone(), b"small".to_vec()).unwrap();
+
+        assert_eq!(store.remove(&p).unwrap(), Some(b"small".to_vec()));
+        assert_eq!(store.remove(&p).unwrap(), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn oversized_blocks_spill_to_disk_and_round_trip() {
+        let dir = temp_spill_dir("spill");
+        let mut store = BlockStore::new(dir.clone(), Some(4));
+
+        let p = point(10, 1);
+        let bytes = b"this is bigger than the inline threshold".to_vec();
+        store.insert(p.clone(), bytes.clone()).unwrap();
+
+        assert!(store.spill_path(&p).exists());
+        assert_eq!(store.remove(&p).unwrap(), Some(bytes));
+        assert!(!store.spill_path(&p).exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn retain_up_to_slot_drops_only_later_points() {
+        let dir = temp_spill_dir("retain");
+        let mut store = BlockStore::new(dir.clone(), Some(1024));
+
+        let a = point(10, 1);
+        let b = point(20, 2);
+
+        store.insert(a.clone(), b"a".to_vec()).unwrap();
+        store.insert(b.clone(), b"b".to_vec()).unwrap();
+
+        store.retain_up_to_slot(10).unwrap();
+
+        assert_eq!(store.remove(&a).unwrap(), Some(b"a".to_vec()));
+        assert_eq!(store.remove(&b).unwrap(), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn adopt_spilled_recognizes_a_file_left_over_from_before_a_restart() {
+        let dir = temp_spill_dir("adopt");
+        let p = point(10, 1);
+
+        {
+            let mut store = BlockStore::new(dir.clone(), Some(4));
+            store.insert(p.clone(), b"bigger than four bytes".to_vec()).unwrap();
+            // simulate the process exiting without clearing in-memory state
+        }
+
+        let mut store = BlockStore::new(dir.clone(), Some(4));
+        assert!(store.adopt_spilled(p.clone()).unwrap());
+        assert_eq!(
+            store.remove(&p).unwrap(),
+            Some(b"bigger than four bytes".to_vec())
+        );
+
+        // nothing was ever spilled for a point we never inserted
+        assert!(!store.adopt_spilled(point(20, 2)).unwrap());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,117 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::{
+    mapper,
+    pipelining::{PartialBootstrapResult, SourceProvider, StageSender},
+    sources::{AddressArg, FinalizeConfig, IntersectArg, MagicArg, RetryPolicy},
+    utils::{Utils, WithUtils},
+};
+
+mod blocks;
+mod run;
+mod store;
+mod verify;
+mod workers;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// decoded blocks are buffered and emitted as-is (default)
+    #[default]
+    Disabled,
+
+    /// check that a decoded block declares the last buffered point as its
+    /// predecessor before buffering it; a failure drops the connection and
+    /// surfaces a dedicated error event. The header's recorded body hash is
+    /// not recomputed.
+    Enabled,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PersistenceConfig {
+    /// path to the directory used to persist the rollback buffer and cursor
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct BlockSpillConfig {
+    /// directory used to store blocks that spill over `inline_threshold`
+    pub path: PathBuf,
+
+    /// blocks encoding to more bytes than this stay on disk instead of in
+    /// memory. Defaults to 16 KiB.
+    pub inline_threshold: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct WorkersConfig {
+    /// number of decode/map worker threads. Defaults to 1.
+    pub worker_count: Option<usize>,
+
+    /// how many confirmed blocks may queue up waiting for a worker before
+    /// the receive loop blocks. Defaults to `4 * worker_count`.
+    pub channel_bound: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    pub address: AddressArg,
+    pub magic: Option<MagicArg>,
+
+    #[deprecated(note = "use `intersect` instead")]
+    #[serde(default)]
+    pub since: Option<crate::sources::PointArg>,
+
+    pub intersect: Option<IntersectArg>,
+
+    pub min_depth: usize,
+
+    #[serde(default)]
+    pub mapper: mapper::Config,
+
+    pub finalize: Option<FinalizeConfig>,
+
+    pub retry_policy: Option<RetryPolicy>,
+
+    /// enables crash-safe resume by persisting the confirmed cursor and
+    /// rollback buffer to an embedded store
+    #[serde(default)]
+    pub persistence: Option<PersistenceConfig>,
+
+    /// spills large buffered blocks to disk instead of keeping them in
+    /// memory for the whole `min_depth` window
+    #[serde(default)]
+    pub block_spill: Option<BlockSpillConfig>,
+
+    /// moves event mapping onto a worker pool so the chainsync receive loop
+    /// doesn't wait on the mapper pass. The receive loop still decodes each
+    /// block once to learn its point, and blocks while the worker channel is
+    /// full
+    #[serde(default)]
+    pub workers: Option<WorkersConfig>,
+
+    /// check that a decoded block chains from the last buffered point before
+    /// it is buffered and emitted; the block's body hash is not checked
+    #[serde(default)]
+    pub verify: VerifyMode,
+
+    /// blocks larger than this many bytes are quarantined: instead of being
+    /// buffered and mapped as usual, a dedicated oversize event is emitted
+    /// in their place. Unbounded by default.
+    #[serde(default)]
+    pub max_block_size: Option<usize>,
+}
+
+impl SourceProvider for WithUtils<Config> {
+    fn bootstrap(&self, output: StageSender) -> PartialBootstrapResult {
+        let config = self.inner.clone();
+        let utils = self.utils.clone();
+
+        let handle = std::thread::spawn(move || {
+            run::do_chainsync(&config, utils, output).expect("chainsync loop failed");
+        });
+
+        Ok(handle)
+    }
+}
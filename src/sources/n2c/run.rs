@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fmt::Debug, ops::Deref, sync::Arc, time::Duration};
+use std::{collections::VecDeque, fmt::Debug, ops::Deref, sync::Arc, time::Duration};
 
 use pallas::{
     ledger::traverse::MultiEraBlock,
@@ -12,20 +12,107 @@ use crate::{
     mapper::EventWriter,
     pipelining::StageSender,
     sources::{
-        intersect_starting_point, setup_multiplexer, should_finalize, unknown_block_to_events,
-        FinalizeConfig,
+        intersect_starting_point, setup_multiplexer, should_finalize, FinalizeConfig,
+        IntersectArg,
     },
     utils::{retry, Utils},
     Error,
 };
 
+use super::{
+    blocks::BlockStore,
+    store::{BufferedBlock, Store},
+    verify,
+    workers::WorkerPool,
+    VerifyMode,
+};
+
+/// a point submitted into the `min_depth`-confirmed pipeline but not yet
+/// provably emitted, along with whatever is needed to tell once it is
+enum PendingPoint {
+    /// handed to the worker pool; only confirmed once `seq` is behind
+    /// `WorkerPool::completed_seq()`
+    Worker { seq: u64, point: Point },
+    /// quarantined as oversized; nothing async tracks this one, so it's
+    /// confirmed the moment it's queued here. It still has to wait its turn
+    /// behind any earlier, still-unconfirmed `Worker` entry though, so the
+    /// persisted confirmed cursor never jumps ahead of unconfirmed work just
+    /// because a later point happened to be oversized
+    Oversized { point: Point },
+}
+
+impl PendingPoint {
+    fn into_point(self) -> Point {
+        match self {
+            PendingPoint::Worker { point, .. } => point,
+            PendingPoint::Oversized { point } => point,
+        }
+    }
+}
+
+/// advance past as many leading `pending` entries as are ready, in strict
+/// FIFO order, and return the last point advanced past, if any. A `Worker`
+/// entry is ready once its sequence number has genuinely succeeded; an
+/// `Oversized` entry is always ready, but only once it's at the front, so it
+/// can never be confirmed ahead of an earlier unconfirmed `Worker` entry.
+fn advance_confirmed(pending: &mut VecDeque<PendingPoint>, completed: u64) -> Option<Point> {
+    let mut confirmed = None;
+
+    loop {
+        let ready = match pending.front() {
+            Some(PendingPoint::Worker { seq, .. }) => *seq < completed,
+            Some(PendingPoint::Oversized { .. }) => true,
+            None => false,
+        };
+
+        if !ready {
+            break;
+        }
+
+        let point = pending.pop_front().expect("front just peeked").into_point();
+        confirmed = Some(point);
+    }
+
+    confirmed
+}
+
 struct ChainObserver {
     chain_buffer: chainsync::RollbackBuffer,
     min_depth: usize,
-    blocks: HashMap<Point, Vec<u8>>,
+    blocks: BlockStore,
     event_writer: EventWriter,
     finalize_config: Option<FinalizeConfig>,
     block_count: u64,
+    store: Option<Arc<Store>>,
+    workers: WorkerPool,
+    verify: VerifyMode,
+    max_block_size: Option<usize>,
+    /// points whose raw bytes exceeded `max_block_size` and were quarantined
+    /// instead of buffered, along with their size
+    oversized: std::collections::HashMap<Point, usize>,
+    /// points that left `chain_buffer` (reached `min_depth`) but aren't
+    /// provably emitted yet; the persisted confirmed cursor must never
+    /// advance past the front of this queue, see `advance_confirmed`
+    pending: VecDeque<PendingPoint>,
+    /// the latest point provably emitted so far
+    confirmed_point: Option<Point>,
+}
+
+enum RollForwardError {
+    /// a worker failed to decode or map a confirmed block; the connection
+    /// should be dropped and re-established
+    Worker(Error),
+    /// a block failed integrity verification; the connection should be
+    /// dropped and re-established
+    Verification(Error),
+    /// anything else, treated as unrecoverable
+    Other(Box<dyn std::error::Error>),
+}
+
+impl<E: std::error::Error + 'static> From<E> for RollForwardError {
+    fn from(err: E) -> Self {
+        RollForwardError::Other(Box::new(err))
+    }
 }
 
 // workaround to put a stop on excessive debug requirement coming from Pallas
@@ -54,13 +141,52 @@ impl ChainObserver {
         &mut self,
         content: chainsync::BlockContent,
         tip: &chainsync::Tip,
-    ) -> Result<Continuation, Box<dyn std::error::Error>> {
-        // parse the block and extract the point of the chain
+    ) -> Result<Continuation, RollForwardError> {
+        // decode on the receive loop to learn the point (the worker pool
+        // decodes it again); the mapper pass happens off-loop in the worker
+        // pool, see `submit` below
         let block = MultiEraBlock::decode(content.deref())?;
         let point = Point::Specific(block.slot(), block.hash().to_vec());
 
-        // store the block for later retrieval
-        self.blocks.insert(point.clone(), content.into());
+        if self.verify == VerifyMode::Enabled {
+            if let Err(err) = verify::verify_block(&block, self.chain_buffer.latest()) {
+                log::error!("block verification failed at {:?}: {}", point, err);
+
+                self.event_writer
+                    .append_verification_error_event(&point, &err.to_string())?;
+
+                return Err(RollForwardError::Verification(err));
+            }
+        }
+
+        let content: Vec<u8> = content.into();
+
+        // guard against unbounded memory use from an oversized or
+        // misbehaving relay: quarantine the block instead of buffering it
+        match self.max_block_size {
+            Some(limit) if content.len() > limit => {
+                log::warn!(
+                    "block at {:?} is {} bytes, over the {} byte limit, quarantining",
+                    point,
+                    content.len(),
+                    limit
+                );
+
+                self.record_oversized(&point, content.len())?;
+                self.oversized.insert(point.clone(), content.len());
+            }
+            _ => {
+                // only persist bytes small enough to be worth inlining;
+                // spilled blocks are already durable under their own
+                // content-addressed path, see `BlockStore::adopt_spilled`
+                let persisted_bytes =
+                    (content.len() <= self.blocks.inline_threshold()).then_some(content.as_slice());
+                self.checkpoint_point(&point, persisted_bytes)?;
+
+                // store the block for later retrieval
+                self.blocks.insert(point.clone(), content)?;
+            }
+        }
 
         // track the new point in our memory buffer
         log::info!("rolling forward to point {:?}", point);
@@ -70,14 +196,35 @@ impl ChainObserver {
         let ready = self.chain_buffer.pop_with_depth(self.min_depth);
         log::debug!("found {} points with required min depth", ready.len());
 
-        // find confirmed block in memory and send down the pipeline
+        // find confirmed block in memory and hand it to the worker pool,
+        // which decodes/maps it and emits events in strict chain order
         for point in ready {
-            let block = self
-                .blocks
-                .remove(&point)
-                .expect("required block not found in memory");
-
-            unknown_block_to_events(&self.event_writer, &block)?;
+            self.forget_point(&point)?;
+
+            if let Some(size) = self.oversized.remove(&point) {
+                self.event_writer.append_oversize_block_event(&point, size)?;
+
+                // nothing async tracks this point, but it still has to wait
+                // its turn behind any earlier unconfirmed worker entry, see
+                // `advance_confirmed`
+                self.pending
+                    .push_back(PendingPoint::Oversized { point: point.clone() });
+            } else {
+                let block = self
+                    .blocks
+                    .remove(&point)?
+                    .expect("required block not found in memory");
+
+                let seq = self
+                    .workers
+                    .submit(point.clone(), block)
+                    .map_err(RollForwardError::Worker)?;
+
+                // only actually confirmed once the worker pool has emitted
+                // it, tracked in `checkpoint` via `completed_seq`
+                self.pending
+                    .push_back(PendingPoint::Worker { seq, point: point.clone() });
+            }
 
             self.block_count += 1;
 
@@ -92,9 +239,65 @@ impl ChainObserver {
         // notify chain tip to the pipeline metrics
         self.event_writer.utils.track_chain_tip(tip.1);
 
+        self.checkpoint()?;
+
+        // surface a worker failure as soon as possible rather than waiting
+        // for the next roll forward
+        self.workers.check_error().map_err(RollForwardError::Worker)?;
+
         Ok(Continuation::Proceed)
     }
 
+    /// record a point entering the unconfirmed buffer
+    fn checkpoint_point(&self, point: &Point, bytes: Option<&[u8]>) -> Result<(), Error> {
+        match &self.store {
+            Some(store) => store.record_point(point, bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// record a point entering the unconfirmed buffer as quarantined: no
+    /// bytes are persisted for it (that's the point of quarantining), so a
+    /// reload can still recognize it rather than panicking when it later
+    /// can't find the bytes it never wrote down, see
+    /// `Store::record_oversized_point`
+    fn record_oversized(&self, point: &Point, size: usize) -> Result<(), Error> {
+        match &self.store {
+            Some(store) => store.record_oversized_point(point, size),
+            None => Ok(()),
+        }
+    }
+
+    /// forget a point that left the unconfirmed buffer, either because it
+    /// reached confirmation depth or was rolled back
+    fn forget_point(&self, point: &Point) -> Result<(), Error> {
+        match &self.store {
+            Some(store) => store.forget_point(point),
+            None => Ok(()),
+        }
+    }
+
+    /// advance the persisted confirmed cursor up to, but never past, the
+    /// last point genuinely emitted so far
+    fn checkpoint(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let completed = self.workers.completed_seq();
+
+        if let Some(point) = advance_confirmed(&mut self.pending, completed) {
+            self.confirmed_point = Some(point);
+        }
+
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        if let Some(point) = &self.confirmed_point {
+            store.save_confirmed_point(point)?;
+        }
+
+        Ok(())
+    }
+
     fn on_rollback(&mut self, point: &Point) -> Result<(), Error> {
         log::info!("rolling block to point {:?}", point);
 
@@ -102,15 +305,30 @@ impl ChainObserver {
             chainsync::RollbackEffect::Handled => {
                 log::debug!("handled rollback within buffer {:?}", point);
 
-                // drain memory blocks after the rollback slot
+                // drain buffered blocks after the rollback slot
                 self.blocks
-                    .retain(|x, _| x.slot_or_default() <= point.slot_or_default());
+                    .retain_up_to_slot(point.slot_or_default())
+                    .map_err(|err| err.to_string())?;
+
+                self.oversized
+                    .retain(|p, _| p.slot_or_default() <= point.slot_or_default());
+
+                if let Some(store) = &self.store {
+                    store
+                        .truncate_after_slot(point.slot_or_default())
+                        .map_err(|err| err.to_string())?;
+                }
             }
             chainsync::RollbackEffect::OutOfScope => {
                 log::debug!("rollback out of buffer scope, sending event down the pipeline");
 
                 // clear all the blocks in memory, they are orphan
                 self.blocks.clear();
+                self.oversized.clear();
+
+                if let Some(store) = &self.store {
+                    store.clear_buffer().map_err(|err| err.to_string())?;
+                }
 
                 self.event_writer.append_rollback_event(point)?;
             }
@@ -118,6 +336,8 @@ impl ChainObserver {
 
         log_buffer_state(&self.chain_buffer);
 
+        self.checkpoint().map_err(|err| err.to_string())?;
+
         Ok(())
     }
 
@@ -129,7 +349,9 @@ impl ChainObserver {
         match msg {
             chainsync::NextResponse::RollForward(c, t) => match self.on_roll_forward(c, &t) {
                 Ok(x) => Ok(x),
-                Err(err) => Err(AttemptError::Other(err)),
+                Err(RollForwardError::Worker(err)) => Err(AttemptError::Recoverable(err)),
+                Err(RollForwardError::Verification(err)) => Err(AttemptError::Recoverable(err)),
+                Err(RollForwardError::Other(err)) => Err(AttemptError::Other(err)),
             },
             chainsync::NextResponse::RollBackward(x, _) => match self.on_rollback(&x) {
                 Ok(_) => Ok(Continuation::Proceed),
@@ -151,17 +373,68 @@ fn observe_forever(
     event_writer: EventWriter,
     min_depth: usize,
     finalize_config: Option<FinalizeConfig>,
+    store: Option<Arc<Store>>,
+    intersection: &chainsync::Point,
+    spill_dir: std::path::PathBuf,
+    inline_threshold: Option<usize>,
+    worker_count: usize,
+    channel_bound: usize,
+    verify: VerifyMode,
+    max_block_size: Option<usize>,
 ) -> Result<(), AttemptError> {
+    let mut chain_buffer = chainsync::RollbackBuffer::default();
+    let mut blocks = BlockStore::new(spill_dir.clone(), inline_threshold);
+    let mut oversized = std::collections::HashMap::new();
+
+    if let Some(store) = &store {
+        let confirmed_point = store.load_confirmed_point().ok().flatten();
+
+        match reload_checkpoint(
+            store,
+            confirmed_point.as_ref(),
+            intersection,
+            spill_dir,
+            inline_threshold,
+        ) {
+            Ok(Some((reloaded_buffer, reloaded_blocks, reloaded_oversized))) => {
+                log::info!(
+                    "resumed {} buffered point(s) from persisted checkpoint",
+                    reloaded_buffer.size()
+                );
+                chain_buffer = reloaded_buffer;
+                blocks = reloaded_blocks;
+                oversized = reloaded_oversized;
+            }
+            Ok(None) => log::info!("no usable checkpoint found, starting with a clean buffer"),
+            Err(err) => {
+                log::warn!("discarding inconsistent checkpoint: {}", err);
+
+                if let Err(err) = store.clear_buffer() {
+                    log::warn!("failed to clear discarded checkpoint: {}", err);
+                }
+            }
+        }
+    }
+
+    let workers = WorkerPool::new(worker_count, channel_bound, event_writer.clone());
+
     let mut observer = ChainObserver {
-        chain_buffer: Default::default(),
-        blocks: HashMap::new(),
+        chain_buffer,
+        blocks,
         min_depth,
         event_writer,
         block_count: 0,
         finalize_config,
+        store,
+        workers,
+        verify,
+        max_block_size,
+        oversized,
+        pending: VecDeque::new(),
+        confirmed_point: None,
     };
 
-    loop {
+    let outcome = loop {
         match client.request_next() {
             Ok(next) => match observer.on_next_message(next, &mut client) {
                 Ok(Continuation::Proceed) => (),
@@ -170,7 +443,78 @@ fn observe_forever(
             },
             Err(err) => break Err(AttemptError::Recoverable(err.into())),
         }
+    };
+
+    observer.workers.shutdown();
+
+    outcome
+}
+
+type ReloadedBuffer = (
+    chainsync::RollbackBuffer,
+    BlockStore,
+    std::collections::HashMap<Point, usize>,
+);
+
+/// reload a persisted checkpoint, verifying it is still consistent with the
+/// point the node agreed to intersect at. An inconsistent checkpoint (for
+/// example, one invalidated by a rollback that happened while Oura was
+/// down) is discarded in favor of a clean sync.
+///
+/// We resumed by asking the node to intersect at `confirmed_point` (see
+/// `do_chainsync_attempt`). If the node still agrees, `intersection` is
+/// exactly that point, by slot *and* hash. If a reorg invalidated it, the
+/// node instead returns an earlier common ancestor, which won't match — a
+/// mismatch here means everything we buffered on top of it is orphaned, so
+/// the checkpoint must be discarded rather than silently reloaded.
+fn reload_checkpoint(
+    store: &Store,
+    confirmed_point: Option<&Point>,
+    intersection: &Point,
+    spill_dir: std::path::PathBuf,
+    inline_threshold: Option<usize>,
+) -> Result<Option<ReloadedBuffer>, Error> {
+    let persisted = store.load_buffer()?;
+
+    if persisted.is_empty() {
+        return Ok(None);
     }
+
+    if let Some(confirmed) = confirmed_point {
+        if confirmed.slot_or_default() != intersection.slot_or_default()
+            || point_hash(confirmed) != point_hash(intersection)
+        {
+            return Err(
+                "persisted confirmed point no longer matches the node's intersection, \
+                 likely invalidated by a rollback while disconnected"
+                    .into(),
+            );
+        }
+    }
+
+    let mut chain_buffer = chainsync::RollbackBuffer::default();
+    let mut blocks = BlockStore::new(spill_dir, inline_threshold);
+    let mut oversized = std::collections::HashMap::new();
+
+    for (point, block) in persisted {
+        chain_buffer.roll_forward(point.clone());
+
+        match block {
+            BufferedBlock::Bytes(bytes) => blocks.insert(point, bytes)?,
+            // deliberately quarantined: no bytes were ever persisted for it,
+            // by design, see `Store::record_oversized_point`
+            BufferedBlock::Oversized(size) => {
+                oversized.insert(point, size);
+            }
+            // not small enough to have been inlined; it may still be sitting
+            // in its content-addressed spill file from before the restart
+            BufferedBlock::Unknown => {
+                blocks.adopt_spilled(point)?;
+            }
+        }
+    }
+
+    Ok(Some((chain_buffer, blocks, oversized)))
 }
 
 #[derive(Debug)]
@@ -217,30 +561,89 @@ fn do_chainsync_attempt(
 
     let mut client = chainsync::N2CClient::new(cs_channel);
 
+    let store = config
+        .persistence
+        .as_ref()
+        .map(|cfg| Store::open(&cfg.path))
+        .transpose()
+        .map_err(AttemptError::Recoverable)?
+        .map(Arc::new);
+
+    // prefer resuming from the last confirmed point persisted to the store
+    // over the configured intersection, so a restart doesn't re-read blocks
+    // that are already known to be confirmed
+    let resume_intersect = match &store {
+        Some(store) => store
+            .load_confirmed_point()
+            .map_err(AttemptError::Recoverable)?
+            .map(|point| IntersectArg::Point(point.slot_or_default(), hex::encode(point_hash(&point)))),
+        None => None,
+    };
+
     let intersection = intersect_starting_point(
         &mut client,
-        &config.intersect,
+        &resume_intersect.or_else(|| config.intersect.clone()),
         #[allow(deprecated)]
         &config.since,
         &utils,
     )
     .map_err(|err| AttemptError::Recoverable(err))?;
 
-    if intersection.is_none() {
-        return Err(AttemptError::Other(
-            "Can't find chain intersection point".into(),
-        ));
-    }
+    let intersection = match intersection {
+        Some(point) => point,
+        None => {
+            return Err(AttemptError::Other(
+                "Can't find chain intersection point".into(),
+            ))
+        }
+    };
 
     log::info!("starting chain sync from: {:?}", &intersection);
 
     let writer = EventWriter::new(output_tx.clone(), utils, config.mapper.clone());
 
-    observe_forever(client, writer, config.min_depth, config.finalize.clone())?;
+    let (spill_dir, inline_threshold) = match &config.block_spill {
+        Some(cfg) => (cfg.path.clone(), cfg.inline_threshold),
+        None => (std::env::temp_dir().join("oura").join("n2c-blocks"), None),
+    };
+
+    let worker_count = config
+        .workers
+        .as_ref()
+        .and_then(|cfg| cfg.worker_count)
+        .unwrap_or(1);
+
+    let channel_bound = config
+        .workers
+        .as_ref()
+        .and_then(|cfg| cfg.channel_bound)
+        .unwrap_or(worker_count * 4);
+
+    observe_forever(
+        client,
+        writer,
+        config.min_depth,
+        config.finalize.clone(),
+        store,
+        &intersection,
+        spill_dir,
+        inline_threshold,
+        worker_count,
+        channel_bound,
+        config.verify,
+        config.max_block_size,
+    )?;
 
     Ok(())
 }
 
+fn point_hash(point: &Point) -> Vec<u8> {
+    match point {
+        Point::Origin => vec![],
+        Point::Specific(_, hash) => hash.clone(),
+    }
+}
+
 pub fn do_chainsync(
     config: &super::Config,
     utils: Arc<Utils>,
@@ -273,3 +676,59 @@ pub fn do_chainsync(
         },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(slot: u64, hash_byte: u8) -> Point {
+        Point::Specific(slot, vec![hash_byte; 32])
+    }
+
+    #[test]
+    fn advances_past_a_succeeded_worker_entry() {
+        let mut pending = VecDeque::new();
+        pending.push_back(PendingPoint::Worker { seq: 0, point: point(10, 1) });
+        pending.push_back(PendingPoint::Worker { seq: 1, point: point(20, 2) });
+
+        // only seq 0 has completed so far
+        assert_eq!(advance_confirmed(&mut pending, 1), Some(point(10, 1)));
+        assert_eq!(pending.len(), 1);
+
+        // nothing new has completed; no further advancement
+        assert_eq!(advance_confirmed(&mut pending, 1), None);
+    }
+
+    #[test]
+    fn an_oversized_entry_waits_behind_an_earlier_unconfirmed_worker_entry() {
+        // reproduces the bug: a normal block is submitted to the worker pool
+        // and not yet drained, then a later point is oversized and queued
+        // right behind it. The oversized entry must not be confirmed ahead
+        // of the still-unconfirmed worker entry.
+        let mut pending = VecDeque::new();
+        pending.push_back(PendingPoint::Worker { seq: 0, point: point(10, 1) });
+        pending.push_back(PendingPoint::Oversized { point: point(20, 2) });
+
+        // the worker item hasn't completed yet
+        assert_eq!(advance_confirmed(&mut pending, 0), None);
+        assert_eq!(pending.len(), 2);
+
+        // once it completes, both advance together, in order
+        assert_eq!(advance_confirmed(&mut pending, 1), Some(point(20, 2)));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn a_stuck_worker_entry_blocks_all_advancement_behind_it() {
+        // simulates a worker failure: completed_seq() never passes the
+        // failed item's sequence number, so nothing behind it - oversized
+        // or not - is ever mistaken for confirmed
+        let mut pending = VecDeque::new();
+        pending.push_back(PendingPoint::Worker { seq: 0, point: point(10, 1) });
+        pending.push_back(PendingPoint::Oversized { point: point(20, 2) });
+        pending.push_back(PendingPoint::Worker { seq: 1, point: point(30, 3) });
+
+        assert_eq!(advance_confirmed(&mut pending, 0), None);
+        assert_eq!(pending.len(), 3);
+    }
+}
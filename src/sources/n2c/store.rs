@@ -0,0 +1,298 @@
+//! Embedded persistence for the chainsync rollback buffer.
+//!
+//! Keeps a durable record of the confirmed cursor and the unconfirmed
+//! points still sitting inside the `min_depth` window so a restart can
+//! resume from the last confirmed point instead of re-intersecting from the
+//! config default, and reload the unconfirmed points into the buffer. Blocks
+//! after the confirmed point are requested from the node again.
+//!
+//! The buffer is checkpointed incrementally: each point is recorded or
+//! forgotten as it enters or leaves the in-memory buffer, instead of
+//! clearing and rewriting the whole tree on every roll-forward/rollback.
+//! That keeps a deep `min_depth` from turning every confirmed block into an
+//! O(buffer depth) disk round-trip on the chainsync receive loop.
+
+use pallas::network::miniprotocols::Point;
+
+use crate::Error;
+
+const CONFIRMED_KEY: &[u8] = b"confirmed_point";
+const BUFFER_TREE: &str = "buffer_points";
+const BLOCKS_TREE: &str = "buffer_blocks";
+const OVERSIZED_TREE: &str = "buffer_oversized";
+
+/// what, if anything, is known locally about a buffered point's bytes
+pub enum BufferedBlock {
+    /// the bytes were small enough to have been inlined
+    Bytes(Vec<u8>),
+    /// the block was quarantined instead of buffered; this is its size
+    Oversized(usize),
+    /// no bytes were persisted for this point; it may still be recoverable
+    /// from a content-addressed spill file left over from before a restart
+    Unknown,
+}
+
+pub struct Store {
+    db: sled::Db,
+}
+
+impl Store {
+    pub fn open(path: &std::path::Path) -> Result<Self, Error> {
+        let db = sled::open(path)?;
+        Ok(Self { db })
+    }
+
+    /// durably record the latest point that has reached confirmation depth
+    /// and been fully emitted
+    pub fn save_confirmed_point(&self, point: &Point) -> Result<(), Error> {
+        let value = point_to_bytes(point);
+        self.db.insert(CONFIRMED_KEY, value)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn load_confirmed_point(&self) -> Result<Option<Point>, Error> {
+        match self.db.get(CONFIRMED_KEY)? {
+            Some(raw) => Ok(Some(bytes_to_point(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// record a single point entering the unconfirmed buffer, along with its
+    /// bytes when worth persisting (small enough to inline; spilled blocks
+    /// are already durable on disk under their content-addressed path, so
+    /// there's no need to duplicate them here)
+    pub fn record_point(&self, point: &Point, bytes: Option<&[u8]>) -> Result<(), Error> {
+        let key = point_to_bytes(point);
+
+        let points_tree = self.db.open_tree(BUFFER_TREE)?;
+        points_tree.insert(&key, &[])?;
+
+        if let Some(bytes) = bytes {
+            let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
+            blocks_tree.insert(&key, bytes)?;
+        }
+
+        Ok(())
+    }
+
+    /// record a single point entering the unconfirmed buffer as quarantined:
+    /// no bytes are kept for it anywhere (that's the point of quarantining),
+    /// just its size, so a reload can tell it apart from a point whose
+    /// bytes were simply never found rather than never written
+    pub fn record_oversized_point(&self, point: &Point, size: usize) -> Result<(), Error> {
+        let key = point_to_bytes(point);
+
+        self.db.open_tree(BUFFER_TREE)?.insert(&key, &[])?;
+        self.db
+            .open_tree(OVERSIZED_TREE)?
+            .insert(&key, &(size as u64).to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// forget a single point that has left the unconfirmed buffer, either
+    /// because it reached confirmation depth or was rolled back
+    pub fn forget_point(&self, point: &Point) -> Result<(), Error> {
+        let key = point_to_bytes(point);
+
+        self.db.open_tree(BUFFER_TREE)?.remove(&key)?;
+        self.db.open_tree(BLOCKS_TREE)?.remove(&key)?;
+        self.db.open_tree(OVERSIZED_TREE)?.remove(&key)?;
+
+        Ok(())
+    }
+
+    /// drop every buffered point strictly after `slot`, for a rollback that
+    /// lands within the buffer
+    pub fn truncate_after_slot(&self, slot: u64) -> Result<(), Error> {
+        let lower_bound = (slot + 1).to_be_bytes().to_vec();
+
+        let points_tree = self.db.open_tree(BUFFER_TREE)?;
+        let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
+        let oversized_tree = self.db.open_tree(OVERSIZED_TREE)?;
+
+        let stale: Vec<Vec<u8>> = points_tree
+            .range(lower_bound..)
+            .keys()
+            .collect::<Result<_, _>>()?;
+
+        for key in stale {
+            points_tree.remove(&key)?;
+            blocks_tree.remove(&key)?;
+            oversized_tree.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
+    /// drop the entire unconfirmed buffer, for a rollback deeper than the
+    /// buffer can handle
+    pub fn clear_buffer(&self) -> Result<(), Error> {
+        self.db.open_tree(BUFFER_TREE)?.clear()?;
+        self.db.open_tree(BLOCKS_TREE)?.clear()?;
+        self.db.open_tree(OVERSIZED_TREE)?.clear()?;
+        Ok(())
+    }
+
+    /// reload a previously checkpointed buffer, returning the ordered points
+    /// and whatever is known locally about their bytes so the caller can
+    /// repopulate `chain_buffer`, `blocks` and `oversized`
+    pub fn load_buffer(&self) -> Result<Vec<(Point, BufferedBlock)>, Error> {
+        let points_tree = self.db.open_tree(BUFFER_TREE)?;
+        let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
+        let oversized_tree = self.db.open_tree(OVERSIZED_TREE)?;
+
+        let mut out = vec![];
+
+        for entry in points_tree.iter() {
+            let (raw_point, _) = entry?;
+            let point = bytes_to_point(&raw_point)?;
+
+            let block = if let Some(raw_size) = oversized_tree.get(&raw_point)? {
+                let size = u64::from_be_bytes(raw_size.as_ref().try_into()?);
+                BufferedBlock::Oversized(size as usize)
+            } else if let Some(bytes) = blocks_tree.get(&raw_point)? {
+                BufferedBlock::Bytes(bytes.to_vec())
+            } else {
+                BufferedBlock::Unknown
+            };
+
+            out.push((point, block));
+        }
+
+        Ok(out)
+    }
+}
+
+fn point_to_bytes(point: &Point) -> Vec<u8> {
+    match point {
+        Point::Origin => vec![],
+        Point::Specific(slot, hash) => {
+            let mut out = slot.to_be_bytes().to_vec();
+            out.extend_from_slice(hash);
+            out
+        }
+    }
+}
+
+fn bytes_to_point(raw: &[u8]) -> Result<Point, Error> {
+    if raw.is_empty() {
+        return Ok(Point::Origin);
+    }
+
+    if raw.len() < 8 {
+        return Err("corrupt persisted point".into());
+    }
+
+    let (slot_bytes, hash) = raw.split_at(8);
+    let slot = u64::from_be_bytes(slot_bytes.try_into().unwrap());
+
+    Ok(Point::Specific(slot, hash.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store(name: &str) -> (Store, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "oura-test-store-{name}-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        (Store::open(&path).expect("open store"), path)
+    }
+
+    fn point(slot: u64, hash_byte: u8) -> Point {
+        Point::Specific(slot, vec![hash_byte; 32])
+    }
+
+    #[test]
+    fn round_trips_confirmed_point() {
+        let (store, path) = temp_store("confirmed");
+
+        assert!(store.load_confirmed_point().unwrap().is_none());
+
+        let p = point(10, 1);
+        store.save_confirmed_point(&p).unwrap();
+
+        assert_eq!(store.load_confirmed_point().unwrap(), Some(p));
+
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn round_trips_buffer_points_in_slot_order_with_bytes() {
+        let (store, path) = temp_store("buffer");
+
+        let a = point(10, 1);
+        let b = point(20, 2);
+        let c = point(30, 3);
+
+        store.record_point(&b, Some(b"bbb")).unwrap();
+        store.record_point(&a, Some(b"aaa")).unwrap();
+        store.record_oversized_point(&c, 999).unwrap();
+
+        let loaded = store.load_buffer().unwrap();
+        let points: Vec<Point> = loaded.iter().map(|(p, _)| p.clone()).collect();
+        assert_eq!(points, vec![a.clone(), b.clone(), c.clone()]);
+
+        match &loaded[0].1 {
+            BufferedBlock::Bytes(bytes) => assert_eq!(bytes, b"aaa"),
+            _ => panic!("expected inlined bytes for the first point"),
+        }
+
+        match &loaded[2].1 {
+            BufferedBlock::Oversized(size) => assert_eq!(*size, 999),
+            _ => panic!("expected an oversized entry for the third point"),
+        }
+
+        store.forget_point(&a).unwrap();
+        let loaded = store.load_buffer().unwrap();
+        let points: Vec<Point> = loaded.iter().map(|(p, _)| p.clone()).collect();
+        assert_eq!(points, vec![b, c]);
+
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn truncate_after_slot_drops_only_later_points() {
+        let (store, path) = temp_store("truncate");
+
+        let a = point(10, 1);
+        let b = point(20, 2);
+        let c = point(30, 3);
+
+        store.record_point(&a, None).unwrap();
+        store.record_point(&b, None).unwrap();
+        store.record_point(&c, None).unwrap();
+
+        store.truncate_after_slot(20).unwrap();
+
+        let points: Vec<Point> = store
+            .load_buffer()
+            .unwrap()
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(points, vec![a, b]);
+
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn clear_buffer_drops_everything() {
+        let (store, path) = temp_store("clear");
+
+        store.record_point(&point(10, 1), Some(b"x")).unwrap();
+        store.record_oversized_point(&point(20, 2), 5).unwrap();
+
+        store.clear_buffer().unwrap();
+
+        assert!(store.load_buffer().unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -0,0 +1,46 @@
+//! Optional block-integrity verification, run before a freshly decoded
+//! block is buffered and (eventually) emitted down the pipeline. Checks that
+//! the block actually chains from the point we last buffered, catching a
+//! relay that serves a block inconsistent with the chain it claims to be
+//! extending.
+//!
+//! This deliberately does not attempt to recompute the header's recorded
+//! body hash: that requires rebuilding the era-specific Merkle roots over
+//! transaction bodies, witnesses, auxiliary data and the invalid-transaction
+//! list, not a single hash over the encoded transactions, and getting it
+//! wrong would make every valid block look corrupt.
+
+use pallas::{ledger::traverse::MultiEraBlock, network::miniprotocols::Point};
+
+use crate::Error;
+
+/// check that `block` declares the previous point we buffered (`previous`)
+/// as its predecessor. `previous` is `None` whenever the rollback buffer is
+/// empty (such as for the very first block seen) and `Point::Origin` when
+/// the chain tip is the origin; in either case there's nothing to compare
+/// against and the block is accepted: this check
+/// is about catching a relay that serves a block out of sequence with what
+/// we've already buffered, not about validating the chain from genesis.
+pub fn verify_block(block: &MultiEraBlock, previous: Option<&Point>) -> Result<(), Error> {
+    let expected_previous_hash = match previous {
+        Some(Point::Specific(_, hash)) => hash,
+        Some(Point::Origin) | None => return Ok(()),
+    };
+
+    match block.header().previous_hash() {
+        Some(declared) if declared.as_ref() == expected_previous_hash.as_slice() => Ok(()),
+        Some(declared) => Err(format!(
+            "block at slot {} declares previous hash {}, doesn't chain from the last buffered \
+             point (hash {})",
+            block.slot(),
+            hex::encode(declared),
+            hex::encode(expected_previous_hash),
+        )
+        .into()),
+        None => Err(format!(
+            "block at slot {} declares no previous hash, but a predecessor is already buffered",
+            block.slot(),
+        )
+        .into()),
+    }
+}
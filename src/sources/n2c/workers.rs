@@ -0,0 +1,265 @@
+//! Bounded worker pool that maps blocks to events off the chainsync receive
+//! loop, so the mapper pass doesn't throttle the client's ability to request
+//! the next protocol message during historical/bulk sync. The receive loop
+//! still decodes each block once itself to learn its point; workers decode it
+//! again before mapping.
+//!
+//! Workers pull confirmed `(Point, Vec<u8>)` items from a bounded channel
+//! (the bound provides backpressure) and decode concurrently, but only ever
+//! hand events to the `EventWriter` in strict chain order: each item carries
+//! a sequence number and a worker waits on an `OrderingGate` for its turn
+//! *before* calling into the event writer, not after. A failure poisons the
+//! gate instead of being counted as done, so a block whose events never
+//! actually reached the pipeline can never be mistaken for confirmed.
+
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+
+use pallas::{ledger::traverse::MultiEraBlock, network::miniprotocols::Point};
+
+use crate::{mapper::EventWriter, sources::unknown_block_to_events, Error};
+
+struct WorkItem {
+    seq: u64,
+    point: Point,
+    bytes: Vec<u8>,
+}
+
+struct GateState {
+    /// lowest sequence number not yet confirmed done
+    next_seq: u64,
+    /// set once a guarded section fails; no later sequence number is ever
+    /// allowed to run or be counted as completed after that
+    poisoned: bool,
+}
+
+/// lets workers complete out-of-order work (decoding) concurrently while
+/// only ever running a guarded section (emitting) in strict sequence, and
+/// only counting a sequence number as done once its section has succeeded
+struct OrderingGate {
+    state: Mutex<GateState>,
+    cvar: Condvar,
+}
+
+impl OrderingGate {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(GateState {
+                next_seq: 0,
+                poisoned: false,
+            }),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// block until `seq` is next in line (or the gate has been poisoned by
+    /// an earlier failure), then run `section`. Only advances past `seq` on
+    /// success; a failure poisons the gate instead, so no later sequence
+    /// number is ever treated as completed. Returns `None` without running
+    /// `section` if an earlier item already poisoned the gate, so a pool
+    /// full of blocked workers can still be torn down instead of deadlocking.
+    fn run_in_order<T>(
+        &self,
+        seq: u64,
+        section: impl FnOnce() -> Result<T, Error>,
+    ) -> Option<Result<T, Error>> {
+        let mut state = self.state.lock().unwrap();
+
+        while state.next_seq != seq && !state.poisoned {
+            state = self.cvar.wait(state).unwrap();
+        }
+
+        if state.poisoned && state.next_seq != seq {
+            return None;
+        }
+
+        let result = section();
+
+        match &result {
+            Ok(_) => state.next_seq += 1,
+            Err(_) => state.poisoned = true,
+        }
+
+        self.cvar.notify_all();
+
+        Some(result)
+    }
+
+    /// how many sequence numbers (`0..n` for a return value of `n`) have
+    /// genuinely succeeded, in order, so far
+    fn completed(&self) -> u64 {
+        self.state.lock().unwrap().next_seq
+    }
+}
+
+pub struct WorkerPool {
+    work_tx: mpsc::SyncSender<WorkItem>,
+    handles: Vec<std::thread::JoinHandle<()>>,
+    error: Arc<Mutex<Option<Error>>>,
+    ordering: Arc<OrderingGate>,
+    next_seq: u64,
+}
+
+impl WorkerPool {
+    pub fn new(worker_count: usize, channel_bound: usize, event_writer: EventWriter) -> Self {
+        let (work_tx, work_rx) = mpsc::sync_channel::<WorkItem>(channel_bound.max(1));
+        let work_rx = Arc::new(Mutex::new(work_rx));
+        let ordering = Arc::new(OrderingGate::new());
+        let error: Arc<Mutex<Option<Error>>> = Arc::new(Mutex::new(None));
+
+        let handles = (0..worker_count.max(1))
+            .map(|_| {
+                let work_rx = work_rx.clone();
+                let ordering = ordering.clone();
+                let error = error.clone();
+                let event_writer = event_writer.clone();
+
+                std::thread::spawn(move || {
+                    while let Ok(item) = {
+                        let rx = work_rx.lock().unwrap();
+                        rx.recv()
+                    } {
+                        // decoding runs unordered/concurrently across workers;
+                        // only the emit below is gated to preserve chain order
+                        let decoded = decode(&item);
+
+                        match ordering.run_in_order(item.seq, || {
+                            decoded.and_then(|_| unknown_block_to_events(&event_writer, &item.bytes))
+                        }) {
+                            Some(Ok(())) => (),
+                            Some(Err(err)) => {
+                                error.lock().unwrap().get_or_insert(err);
+                            }
+                            // the gate was already poisoned by an earlier
+                            // item; chain order can no longer be guaranteed,
+                            // so stop picking up further work
+                            None => break,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            work_tx,
+            handles,
+            error,
+            ordering,
+            next_seq: 0,
+        }
+    }
+
+    /// hand a confirmed block off to the worker pool, blocking (applying
+    /// backpressure) once the channel is full. Returns the sequence number
+    /// assigned to the item, so the caller can tell once it has actually
+    /// been emitted via [`WorkerPool::completed_seq`].
+    pub fn submit(&mut self, point: Point, bytes: Vec<u8>) -> Result<u64, Error> {
+        self.check_error()?;
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.work_tx
+            .send(WorkItem { seq, point, bytes })
+            .map_err(|_| "worker pool closed unexpectedly")?;
+
+        Ok(seq)
+    }
+
+    pub fn check_error(&self) -> Result<(), Error> {
+        match self.error.lock().unwrap().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// how many submitted items (by sequence number, 0-indexed) have
+    /// genuinely succeeded and been emitted, in order, so far. Never
+    /// advances past an item whose emission failed.
+    pub fn completed_seq(&self) -> u64 {
+        self.ordering.completed()
+    }
+
+    /// stop accepting work and wait for in-flight items to drain
+    pub fn shutdown(self) {
+        drop(self.work_tx);
+
+        for handle in self.handles {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn decode(item: &WorkItem) -> Result<(), Error> {
+    let block = MultiEraBlock::decode(&item.bytes)?;
+
+    if block.slot() != item.point.slot_or_default() {
+        return Err("decoded block slot doesn't match the buffered point".into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrderingGate;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn run_in_order_serializes_sections_by_sequence() {
+        let gate = Arc::new(OrderingGate::new());
+        let observed = Arc::new(Mutex::new(Vec::new()));
+
+        // submit in reverse order across threads; the gate must still only
+        // let each section through once its predecessor has completed
+        let handles: Vec<_> = (0..8)
+            .rev()
+            .map(|seq| {
+                let gate = gate.clone();
+                let observed = observed.clone();
+
+                std::thread::spawn(move || {
+                    gate.run_in_order(seq, || -> Result<(), crate::Error> {
+                        observed.lock().unwrap().push(seq);
+                        Ok(())
+                    });
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(*observed.lock().unwrap(), (0..8).collect::<Vec<_>>());
+        assert_eq!(gate.completed(), 8);
+    }
+
+    #[test]
+    fn a_failed_section_poisons_the_gate_instead_of_advancing() {
+        let gate = OrderingGate::new();
+
+        let first = gate.run_in_order(0, || -> Result<(), crate::Error> { Ok(()) });
+        assert!(matches!(first, Some(Ok(()))));
+        assert_eq!(gate.completed(), 1);
+
+        // this block's events fail to reach the pipeline...
+        let second = gate.run_in_order(1, || -> Result<(), crate::Error> {
+            Err("decode/map failed".into())
+        });
+        assert!(matches!(second, Some(Err(_))));
+
+        // ...so completed_seq must never advance past it, even though the
+        // section ran and returned
+        assert_eq!(gate.completed(), 1);
+
+        // and no later item is ever allowed to run or be mistaken for done
+        let mut later_ran = false;
+        let third = gate.run_in_order(2, || -> Result<(), crate::Error> {
+            later_ran = true;
+            Ok(())
+        });
+        assert!(third.is_none());
+        assert!(!later_ran);
+        assert_eq!(gate.completed(), 1);
+    }
+}